@@ -0,0 +1,843 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{atomic, Arc},
+    thread,
+};
+
+use clap::ValueEnum;
+
+use crate::limit::RateLimiter;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Engine {
+    Reflink,
+    CopyFileRange,
+    Sendfile,
+    Splice,
+    PipeSplice,
+    ReadWrite,
+}
+
+impl Engine {
+    /// Engine to try next, if this one is not supported for given input/output
+    pub fn fallback(self) -> Option<Engine> {
+        match self {
+            Engine::Reflink => Some(Engine::CopyFileRange),
+            Engine::CopyFileRange | Engine::Sendfile => Some(Engine::PipeSplice),
+            Engine::Splice | Engine::PipeSplice => Some(Engine::ReadWrite),
+            Engine::ReadWrite => None,
+        }
+    }
+
+    /// Copies input to output until EOF
+    ///
+    /// Counter is increased by copied bytes, or if delimiter is given, by number of delimiters (lines) copied,
+    /// which is supported only by read/write and splice engines (latter count lines in data duplicated by tee(2)).
+    ///
+    /// If error is unsupported (see [`is_unsupported`]), input and output are left consistent,
+    /// so copy can continue with the fallback engine.
+    pub fn copy<R, W>(
+        self,
+        input: &mut R,
+        output: &mut W,
+        counter: Arc<atomic::AtomicU64>,
+        chunk_size: usize,
+        limiter: &mut RateLimiter,
+        delimiter: Option<u8>,
+    ) -> io::Result<()>
+    where
+        R: AsRawFd + Read,
+        W: AsRawFd + Write,
+    {
+        let fd_in = input.as_raw_fd();
+        let fd_out = output.as_raw_fd();
+        match (self, delimiter) {
+            (Engine::ReadWrite, _) => {
+                rw_copy(input, output, counter, chunk_size, limiter, delimiter)
+            }
+            (Engine::Splice | Engine::PipeSplice, Some(delimiter)) => {
+                let line_counter = LineCounter::start(counter.clone(), delimiter, chunk_size)?;
+                let side = Some(line_counter.fd());
+                let res = if let Engine::Splice = self {
+                    splice_copy(fd_in, fd_out, counter, chunk_size, limiter, side)
+                } else {
+                    pipe_splice_copy(fd_in, fd_out, counter, chunk_size, limiter, side)
+                };
+                line_counter.finish();
+                res
+            }
+            (_, Some(_)) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} engine cannot count lines", self),
+            )),
+            (Engine::Reflink, None) => reflink_copy(fd_in, fd_out, counter),
+            (Engine::CopyFileRange, None) => {
+                copy_file_range_copy(fd_in, fd_out, counter, chunk_size, limiter)
+            }
+            (Engine::Sendfile, None) => sendfile_copy(fd_in, fd_out, counter, chunk_size, limiter),
+            (Engine::Splice, None) => {
+                splice_copy(fd_in, fd_out, counter, chunk_size, limiter, None)
+            }
+            (Engine::PipeSplice, None) => {
+                pipe_splice_copy(fd_in, fd_out, counter, chunk_size, limiter, None)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Engine::Reflink => write!(f, "reflink"),
+            Engine::CopyFileRange => write!(f, "copy_file_range"),
+            Engine::Sendfile => write!(f, "sendfile"),
+            Engine::Splice => write!(f, "splice"),
+            Engine::PipeSplice => write!(f, "splice via internal pipe"),
+            Engine::ReadWrite => write!(f, "read/write"),
+        }
+    }
+}
+
+/// Value of --engine option
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EngineChoice {
+    Auto,
+    Reflink,
+    CopyFileRange,
+    Sendfile,
+    Splice,
+    PipeSplice,
+    Rw,
+}
+
+impl EngineChoice {
+    /// Fixed engine, None for auto
+    pub fn engine(self) -> Option<Engine> {
+        match self {
+            EngineChoice::Auto => None,
+            EngineChoice::Reflink => Some(Engine::Reflink),
+            EngineChoice::CopyFileRange => Some(Engine::CopyFileRange),
+            EngineChoice::Sendfile => Some(Engine::Sendfile),
+            EngineChoice::Splice => Some(Engine::Splice),
+            EngineChoice::PipeSplice => Some(Engine::PipeSplice),
+            EngineChoice::Rw => Some(Engine::ReadWrite),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdKind {
+    Fifo,
+    Socket,
+    File,
+    BlockDevice,
+    Tty,
+    CharDevice,
+    Other,
+    Unknown,
+}
+
+impl FdKind {
+    pub fn of(fd: RawFd) -> Self {
+        let stat = match fstat(fd) {
+            Some(stat) => stat,
+            None => return FdKind::Unknown,
+        };
+        match stat.st_mode & libc::S_IFMT {
+            libc::S_IFIFO => FdKind::Fifo,
+            libc::S_IFSOCK => FdKind::Socket,
+            libc::S_IFREG => FdKind::File,
+            libc::S_IFBLK => FdKind::BlockDevice,
+            libc::S_IFCHR if unsafe { libc::isatty(fd) } == 1 => FdKind::Tty,
+            libc::S_IFCHR => FdKind::CharDevice,
+            _ => FdKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for FdKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FdKind::Fifo => write!(f, "pipe"),
+            FdKind::Socket => write!(f, "socket"),
+            FdKind::File => write!(f, "regular file"),
+            FdKind::BlockDevice => write!(f, "block device"),
+            FdKind::Tty => write!(f, "terminal"),
+            FdKind::CharDevice => write!(f, "character device"),
+            FdKind::Other => write!(f, "other"),
+            FdKind::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Picks fastest engine, which is likely to work for given input and output, returns also reason for the choice
+pub fn select_engine(kind_in: FdKind, kind_out: FdKind) -> (Engine, &'static str) {
+    match (kind_in, kind_out) {
+        (FdKind::Tty, _) | (_, FdKind::Tty) => (
+            Engine::ReadWrite,
+            "terminal does not support splice, using plain read/write",
+        ),
+        (FdKind::Fifo, _) | (_, FdKind::Fifo) => (
+            Engine::Splice,
+            "one side is pipe, so data can be spliced directly",
+        ),
+        (FdKind::File, FdKind::File) => (
+            Engine::Reflink,
+            "both sides are regular files, trying reflink, then copy_file_range",
+        ),
+        (FdKind::File, FdKind::Socket) => (
+            Engine::Sendfile,
+            "copying regular file to socket, sendfile is most efficient",
+        ),
+        _ => (
+            Engine::PipeSplice,
+            "none of sides is pipe, splicing via internal pipe",
+        ),
+    }
+}
+
+/// Returns true if error means that engine cannot be used for given input/output
+pub fn is_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINVAL | libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EBADF)
+    )
+}
+
+fn fstat(fd: RawFd) -> Option<libc::stat> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        None
+    } else {
+        Some(stat)
+    }
+}
+
+fn offset(fd: RawFd) -> io::Result<u64> {
+    let res = unsafe { libc::lseek(fd, 0, libc::SEEK_CUR) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as u64)
+    }
+}
+
+// direction bits of _IOR, these architectures have 3 direction bits and 13 size bits
+#[cfg(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips32r6",
+    target_arch = "mips64",
+    target_arch = "mips64r6",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+))]
+const IOC_READ: libc::c_ulong = 2 << 29;
+#[cfg(not(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips32r6",
+    target_arch = "mips64",
+    target_arch = "mips64r6",
+    target_arch = "sparc",
+    target_arch = "sparc64"
+)))]
+const IOC_READ: libc::c_ulong = 2 << 30;
+
+/// Request number of read ioctl, like _IOR macro
+const fn ior(ty: u8, nr: u8, size: usize) -> libc::c_ulong {
+    IOC_READ | (size as libc::c_ulong) << 16 | (ty as libc::c_ulong) << 8 | nr as libc::c_ulong
+}
+
+// _IOR(0x12, 114, size_t), not exported by libc crate
+const BLKGETSIZE64: libc::c_ulong = ior(0x12, 114, mem::size_of::<libc::size_t>());
+
+/// Detects remaining size of input, if it is regular file or block device
+pub fn input_size(fd: RawFd) -> Option<u64> {
+    let stat = fstat(fd)?;
+
+    let size = match stat.st_mode & libc::S_IFMT {
+        // procfs and sysfs files report zero size, though they have content
+        libc::S_IFREG if stat.st_size == 0 => return None,
+        libc::S_IFREG => stat.st_size as u64,
+        libc::S_IFBLK => {
+            let mut size: u64 = 0;
+            if unsafe { libc::ioctl(fd, BLKGETSIZE64, &mut size) } < 0 {
+                return None;
+            }
+            size
+        }
+        _ => return None,
+    };
+
+    // input might have been already partly consumed, e.g. (head -c 1M >/dev/null; rpv) < file
+    let offset = offset(fd).unwrap_or(0);
+    Some(size.saturating_sub(offset))
+}
+
+/// Clones whole input file into empty output file, works only on filesystems supporting reflinks (btrfs, xfs)
+fn reflink_copy<R, W>(input: R, output: W, counter: Arc<atomic::AtomicU64>) -> io::Result<()>
+where
+    R: AsRawFd,
+    W: AsRawFd,
+{
+    let fd_in = input.as_raw_fd();
+    let fd_out = output.as_raw_fd();
+
+    let size_in = fstat(fd_in).ok_or_else(io::Error::last_os_error)?.st_size;
+    let size_out = fstat(fd_out).ok_or_else(io::Error::last_os_error)?.st_size;
+    // FICLONE always clones whole file, so it is only usable when nothing has been consumed or written yet
+    if offset(fd_in)? != 0 || offset(fd_out)? != 0 || size_out != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    if unsafe { libc::ioctl(fd_out, libc::FICLONE, fd_in) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // move offsets to the end, as if data were copied
+    unsafe {
+        libc::lseek(fd_in, size_in, libc::SEEK_SET);
+        libc::lseek(fd_out, size_in, libc::SEEK_SET);
+    }
+    counter.fetch_add(size_in as u64, atomic::Ordering::Relaxed);
+
+    Ok(())
+}
+
+fn copy_file_range_copy<R, W>(
+    input: R,
+    output: W,
+    counter: Arc<atomic::AtomicU64>,
+    chunk_size: usize,
+    limiter: &mut RateLimiter,
+) -> io::Result<()>
+where
+    R: AsRawFd,
+    W: AsRawFd,
+{
+    let fd_in = input.as_raw_fd();
+    let fd_out = output.as_raw_fd();
+
+    loop {
+        let res = unsafe {
+            libc::copy_file_range(
+                fd_in,
+                std::ptr::null_mut::<libc::loff_t>(),
+                fd_out,
+                std::ptr::null_mut::<libc::loff_t>(),
+                limiter.next_chunk(chunk_size),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        } else if res == 0 {
+            break;
+        }
+        limiter.consume(res as usize);
+        counter.fetch_add(res as u64, atomic::Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+fn sendfile_copy<R, W>(
+    input: R,
+    output: W,
+    counter: Arc<atomic::AtomicU64>,
+    chunk_size: usize,
+    limiter: &mut RateLimiter,
+) -> io::Result<()>
+where
+    R: AsRawFd,
+    W: AsRawFd,
+{
+    let fd_in = input.as_raw_fd();
+    let fd_out = output.as_raw_fd();
+
+    loop {
+        let res = unsafe {
+            libc::sendfile(
+                fd_out,
+                fd_in,
+                std::ptr::null_mut::<libc::off_t>(),
+                limiter.next_chunk(chunk_size),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        } else if res == 0 {
+            break;
+        }
+        limiter.consume(res as usize);
+        counter.fetch_add(res as u64, atomic::Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, size: usize) -> Result<usize, io::Error> {
+    let res = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut::<libc::loff_t>(),
+            fd_out,
+            std::ptr::null_mut::<libc::loff_t>(),
+            size,
+            0,
+        )
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Duplicates data from input pipe with tee(2), without consuming them
+fn tee(fd_in: RawFd, fd_out: RawFd, size: usize) -> Result<usize, io::Error> {
+    let res = unsafe { libc::tee(fd_in, fd_out, size, 0) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Creates pipe, returns (read end, write end)
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Sets pipe capacity and returns the actual one, failure just means smaller chunks
+fn set_pipe_size(fd: RawFd, size: usize) -> Option<usize> {
+    let res = unsafe { libc::fcntl(fd, libc::F_SETPIPE_SZ, size as libc::c_int) };
+    if res > 0 {
+        Some(res as usize)
+    } else {
+        None
+    }
+}
+
+/// Counts lines in data duplicated by tee(2) to side pipe, so main data can be still spliced
+struct LineCounter {
+    side_in: OwnedFd,
+    thread: thread::JoinHandle<()>,
+}
+
+impl LineCounter {
+    fn start(
+        counter: Arc<atomic::AtomicU64>,
+        delimiter: u8,
+        chunk_size: usize,
+    ) -> io::Result<Self> {
+        let (side_out, side_in) = pipe()?;
+        set_pipe_size(side_in.as_raw_fd(), chunk_size);
+        let thread = thread::spawn(move || {
+            let mut side_out = File::from(side_out);
+            let mut buffer = vec![0u8; chunk_size];
+            // read ends with EOF, when write end is closed in finish
+            while let Ok(read @ 1..) = side_out.read(&mut buffer) {
+                counter.fetch_add(
+                    count_delimiters(&buffer[..read], delimiter),
+                    atomic::Ordering::Relaxed,
+                );
+            }
+        });
+        Ok(LineCounter { side_in, thread })
+    }
+
+    fn fd(&self) -> RawFd {
+        self.side_in.as_raw_fd()
+    }
+
+    /// Waits until all data duplicated so far are counted
+    fn finish(self) {
+        drop(self.side_in);
+        let _ = self.thread.join();
+    }
+}
+
+/// Duplicates up to size bytes from pipe to side pipe, then moves the same bytes to output
+///
+/// tee(2) does not consume data, so data must be moved before next tee, otherwise they would be counted twice.
+fn tee_splice(fd_pipe: RawFd, fd_side: RawFd, fd_out: RawFd, size: usize) -> io::Result<usize> {
+    let teed = tee(fd_pipe, fd_side, size)?;
+    let mut moved = 0;
+    while moved < teed {
+        match splice(fd_pipe, fd_out, teed - moved) {
+            Ok(written) => moved += written,
+            Err(e) => {
+                // data are already counted, so flush them out before caller can fall back
+                if is_unsupported(&e) {
+                    drain_pipe(fd_pipe, fd_out, teed - moved)?;
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(teed)
+}
+
+/// Splices input to output, if side pipe is given, data are also duplicated there with tee(2) (input must be pipe then)
+fn splice_copy<R, W>(
+    input: R,
+    output: W,
+    counter: Arc<atomic::AtomicU64>,
+    chunk_size: usize,
+    limiter: &mut RateLimiter,
+    side: Option<RawFd>,
+) -> io::Result<()>
+where
+    R: AsRawFd,
+    W: AsRawFd,
+{
+    let fd_in = input.as_raw_fd();
+    let fd_out = output.as_raw_fd();
+
+    loop {
+        let size = limiter.next_chunk(chunk_size);
+        let written = match side {
+            Some(fd_side) => tee_splice(fd_in, fd_side, fd_out, size)?,
+            None => splice(fd_in, fd_out, size)?,
+        };
+        if written == 0 {
+            break;
+        }
+        limiter.consume(written);
+        if side.is_none() {
+            counter.fetch_add(written as u64, atomic::Ordering::Relaxed);
+        }
+    }
+
+    Ok(())
+}
+
+/// Splices input to output through internal pipe, so it works also when none of them is pipe
+///
+/// If side pipe is given, data are also duplicated there with tee(2).
+fn pipe_splice_copy<R, W>(
+    input: R,
+    output: W,
+    counter: Arc<atomic::AtomicU64>,
+    chunk_size: usize,
+    limiter: &mut RateLimiter,
+    side: Option<RawFd>,
+) -> io::Result<()>
+where
+    R: AsRawFd,
+    W: AsRawFd,
+{
+    let fd_in = input.as_raw_fd();
+    let fd_out = output.as_raw_fd();
+
+    let (pipe_out, pipe_in) = pipe()?;
+    // pipe capacity limits how much can be moved in one splice
+    let chunk_size = match set_pipe_size(pipe_in.as_raw_fd(), chunk_size) {
+        Some(pipe_size) => chunk_size.min(pipe_size),
+        None => chunk_size,
+    };
+
+    loop {
+        let mut pending = splice(fd_in, pipe_in.as_raw_fd(), limiter.next_chunk(chunk_size))?;
+        if pending == 0 {
+            break;
+        }
+        limiter.consume(pending);
+        while pending > 0 {
+            let written = match side {
+                Some(fd_side) => tee_splice(pipe_out.as_raw_fd(), fd_side, fd_out, pending)?,
+                None => match splice(pipe_out.as_raw_fd(), fd_out, pending) {
+                    Ok(written) => written,
+                    Err(e) => {
+                        // data are already in internal pipe, so flush them out before caller can fall back
+                        if is_unsupported(&e) {
+                            drain_pipe(pipe_out.as_raw_fd(), fd_out, pending)?;
+                            counter.fetch_add(pending as u64, atomic::Ordering::Relaxed);
+                        }
+                        return Err(e);
+                    }
+                },
+            };
+            pending -= written;
+            if side.is_none() {
+                counter.fetch_add(written as u64, atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves data from pipe to output with plain read/write
+fn drain_pipe(fd_pipe: RawFd, fd_out: RawFd, pending: usize) -> io::Result<()> {
+    // ManuallyDrop, because both fds are owned elsewhere
+    let input = mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd_pipe) });
+    let output = mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd_out) });
+    let mut buffer = vec![0u8; pending];
+    (&*input).read_exact(&mut buffer)?;
+    (&*output).write_all(&buffer)?;
+    Ok(())
+}
+
+fn rw_copy<R, W>(
+    mut input: R,
+    mut output: W,
+    counter: Arc<atomic::AtomicU64>,
+    chunk_size: usize,
+    limiter: &mut RateLimiter,
+    delimiter: Option<u8>,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut buffer = vec![0u8; chunk_size];
+    loop {
+        let max = limiter.next_chunk(chunk_size);
+        let mut read = input.read(&mut buffer[..max])?;
+
+        if read == 0 {
+            break;
+        }
+        limiter.consume(read);
+
+        let mut offset = 0;
+
+        while read > 0 {
+            let written = output.write(&buffer[offset..offset + read])?;
+            let count = match delimiter {
+                Some(delimiter) => count_delimiters(&buffer[offset..offset + written], delimiter),
+                None => written as u64,
+            };
+            offset += written;
+            read -= written;
+
+            counter.fetch_add(count, atomic::Ordering::Relaxed);
+        }
+    }
+
+    Ok(())
+}
+
+fn count_delimiters(data: &[u8], delimiter: u8) -> u64 {
+    data.iter().filter(|&&b| b == delimiter).count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, OpenOptions},
+        os::unix::fs::OpenOptionsExt,
+        path::PathBuf,
+    };
+
+    use super::*;
+
+    const CHUNK_SIZE: usize = 4096;
+
+    /// Lines of varying length, last one without delimiter
+    fn data(delimiter: u8) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..20000 {
+            data.extend_from_slice(format!("line {}", "x".repeat(i % 37)).as_bytes());
+            data.push(delimiter);
+        }
+        data.extend_from_slice(b"unfinished");
+        data
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rpv-test-{}-{}", std::process::id(), name))
+    }
+
+    /// Read end of pipe, which is filled with data by another thread
+    fn pipe_with(data: &[u8]) -> File {
+        let (read, write) = pipe().unwrap();
+        let data = data.to_vec();
+        thread::spawn(move || {
+            let _ = File::from(write).write_all(&data);
+        });
+        File::from(read)
+    }
+
+    fn file_with(name: &str, data: &[u8]) -> File {
+        let path = temp_path(name);
+        fs::write(&path, data).unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    /// Output opened with O_APPEND, which splice, sendfile and copy_file_range refuse
+    fn append_output(name: &str) -> (File, PathBuf) {
+        let path = temp_path(name);
+        let _ = fs::remove_file(&path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&path)
+            .unwrap();
+        (file, path)
+    }
+
+    fn plain_output(name: &str) -> (File, PathBuf) {
+        let path = temp_path(name);
+        (File::create(&path).unwrap(), path)
+    }
+
+    fn read_output(path: PathBuf) -> Vec<u8> {
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        data
+    }
+
+    /// Copies with fallback like main does, returns count and engine, which finished copying
+    fn copy_all(
+        mut engine: Engine,
+        input: &mut File,
+        output: &mut File,
+        delimiter: Option<u8>,
+    ) -> (u64, Engine) {
+        let counter = Arc::new(atomic::AtomicU64::new(0));
+        let mut limiter = RateLimiter::new(Arc::new(atomic::AtomicU64::new(0)), 0);
+        loop {
+            match engine.copy(
+                input,
+                output,
+                counter.clone(),
+                CHUNK_SIZE,
+                &mut limiter,
+                delimiter,
+            ) {
+                Ok(()) => return (counter.load(atomic::Ordering::Relaxed), engine),
+                Err(e) if is_unsupported(&e) => engine = engine.fallback().unwrap(),
+                Err(e) => panic!("{} engine failed: {}", engine, e),
+            }
+        }
+    }
+
+    #[test]
+    fn splice_falls_back_to_read_write() {
+        let data = data(b'\n');
+        let mut input = pipe_with(&data);
+        let (mut output, path) = append_output("splice-fallback");
+        let (count, engine) = copy_all(Engine::Splice, &mut input, &mut output, None);
+        assert!(matches!(engine, Engine::ReadWrite));
+        assert_eq!(count, data.len() as u64);
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn pipe_splice_drains_internal_pipe_before_fallback() {
+        let data = data(b'\n');
+        let mut input = file_with("pipe-splice-in", &data);
+        let (mut output, path) = append_output("pipe-splice-fallback");
+        let (count, engine) = copy_all(Engine::PipeSplice, &mut input, &mut output, None);
+        assert!(matches!(engine, Engine::ReadWrite));
+        assert_eq!(count, data.len() as u64);
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn copy_file_range_falls_back_through_whole_chain() {
+        let data = data(b'\n');
+        let mut input = file_with("cfr-in", &data);
+        let (mut output, path) = append_output("cfr-fallback");
+        let (count, engine) = copy_all(Engine::CopyFileRange, &mut input, &mut output, None);
+        assert!(matches!(engine, Engine::ReadWrite));
+        assert_eq!(count, data.len() as u64);
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn sendfile_falls_back() {
+        let data = data(b'\n');
+        let mut input = file_with("sendfile-in", &data);
+        let (mut output, path) = append_output("sendfile-fallback");
+        let (count, engine) = copy_all(Engine::Sendfile, &mut input, &mut output, None);
+        assert!(matches!(engine, Engine::ReadWrite));
+        assert_eq!(count, data.len() as u64);
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn splice_without_fallback() {
+        let data = data(b'\n');
+        let mut input = pipe_with(&data);
+        let (mut output, path) = plain_output("splice");
+        let (count, engine) = copy_all(Engine::Splice, &mut input, &mut output, None);
+        assert!(matches!(engine, Engine::Splice));
+        assert_eq!(count, data.len() as u64);
+        assert_eq!(read_output(path), data);
+    }
+
+    fn count(data: &[u8], delimiter: u8) -> u64 {
+        data.iter().filter(|&&b| b == delimiter).count() as u64
+    }
+
+    #[test]
+    fn splice_counts_lines_in_side_pipe() {
+        let data = data(b'\n');
+        let mut input = pipe_with(&data);
+        let (mut output, path) = plain_output("splice-lines");
+        let (lines, engine) = copy_all(Engine::Splice, &mut input, &mut output, Some(b'\n'));
+        assert!(matches!(engine, Engine::Splice));
+        assert_eq!(lines, count(&data, b'\n'));
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn splice_counts_nul_delimited_records() {
+        let data = data(0);
+        let mut input = pipe_with(&data);
+        let (mut output, path) = plain_output("splice-nul");
+        let (records, _) = copy_all(Engine::Splice, &mut input, &mut output, Some(0));
+        assert_eq!(records, count(&data, 0));
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn pipe_splice_counts_lines_from_file() {
+        let data = data(b'\n');
+        let mut input = file_with("pipe-splice-lines-in", &data);
+        let (mut output, path) = plain_output("pipe-splice-lines");
+        let (lines, engine) = copy_all(Engine::PipeSplice, &mut input, &mut output, Some(b'\n'));
+        assert!(matches!(engine, Engine::PipeSplice));
+        assert_eq!(lines, count(&data, b'\n'));
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn lines_are_not_counted_twice_on_fallback() {
+        let data = data(b'\n');
+        let mut input = pipe_with(&data);
+        let (mut output, path) = append_output("splice-lines-fallback");
+        let (lines, engine) = copy_all(Engine::Splice, &mut input, &mut output, Some(b'\n'));
+        assert!(matches!(engine, Engine::ReadWrite));
+        assert_eq!(lines, count(&data, b'\n'));
+        assert_eq!(read_output(path), data);
+    }
+
+    #[test]
+    fn pipe_splice_lines_fallback_from_file() {
+        let data = data(0);
+        let mut input = file_with("pipe-splice-nul-in", &data);
+        let (mut output, path) = append_output("pipe-splice-nul-fallback");
+        let (records, engine) = copy_all(Engine::PipeSplice, &mut input, &mut output, Some(0));
+        assert!(matches!(engine, Engine::ReadWrite));
+        assert_eq!(records, count(&data, 0));
+        assert_eq!(read_output(path), data);
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    mem,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::reporter::Stats;
+
+pub const DEFAULT_FORMAT: &str = "%b %r (avg %a) %s";
+pub const DEFAULT_FORMAT_WITH_TOTAL: &str = "%p %B %b %r (avg %a) ETA %e %s";
+
+const IEC_PREFIXES: [&str; 7] = ["", "Ki", "Mi", "Gi", "Ti", "Pi", "Ei"];
+const SI_PREFIXES: [&str; 7] = ["", "k", "M", "G", "T", "P", "E"];
+
+/// Prefixes used for scaling bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefixes {
+    /// powers of 1024, e.g. KiB
+    Iec,
+    /// powers of 1000, e.g. kB
+    Si,
+}
+
+impl Prefixes {
+    /// Scales value, so it is below next power, returns scaled value and prefix
+    fn scale(self, value: f64) -> (f64, &'static str) {
+        let (base, prefixes) = match self {
+            Prefixes::Iec => (1024.0, &IEC_PREFIXES),
+            Prefixes::Si => (1000.0, &SI_PREFIXES),
+        };
+        let mut value = value;
+        let mut i = 0;
+        while value >= base && i < prefixes.len() - 1 {
+            value /= base;
+            i += 1;
+        }
+        (value, prefixes[i])
+    }
+}
+
+/// What is counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    /// bytes with automatically scaled prefix, rates can be shown in bits per second
+    Bytes {
+        prefixes: Prefixes,
+        bits: bool,
+    },
+    Lines,
+}
+
+impl Unit {
+    pub fn amount(self, count: u64) -> String {
+        match self {
+            Unit::Bytes { prefixes, .. } => match prefixes.scale(count as f64) {
+                (_, "") => format!("{} B", count),
+                (value, prefix) => format!("{:0.2} {}B", value, prefix),
+            },
+            Unit::Lines => format!("{} lines", count),
+        }
+    }
+
+    pub fn rate(self, rate: f64) -> String {
+        match self {
+            Unit::Bytes { prefixes, bits } => {
+                let (rate, symbol) = if bits {
+                    (rate * 8.0, "bit/s")
+                } else {
+                    (rate, "B/s")
+                };
+                match prefixes.scale(rate) {
+                    (value, "") => format!("{:0.0} {}", value, symbol),
+                    (value, prefix) => format!("{:0.2} {}{}", value, prefix, symbol),
+                }
+            }
+            Unit::Lines => format!("{:0.1} lines/s", rate),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Bytes,
+    Elapsed,
+    Rate,
+    Average,
+    Eta,
+    Percent,
+    Bar,
+    Stall,
+    Name,
+}
+
+/// Parsed status line format
+///
+/// Recognized tokens are `%b` bytes (or lines), `%t` elapsed time, `%r` current rate, `%a` average rate,
+/// `%e` ETA, `%p` percentage, `%B` progress bar filling rest of line, `%s` time since last data
+/// (empty while data flow), `%N` name of instance and `%%` for percent sign.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Format {
+    tokens: Vec<Token>,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                literal.push(c);
+                continue;
+            }
+            let token = match chars.next() {
+                Some('%') => {
+                    literal.push('%');
+                    continue;
+                }
+                Some('b') => Token::Bytes,
+                Some('t') => Token::Elapsed,
+                Some('r') => Token::Rate,
+                Some('a') => Token::Average,
+                Some('e') => Token::Eta,
+                Some('p') => Token::Percent,
+                Some('B') => Token::Bar,
+                Some('s') => Token::Stall,
+                Some('N') => Token::Name,
+                Some(other) => return Err(format!("unknown format token %{}", other)),
+                None => return Err("format ends with unfinished %".to_string()),
+            };
+            if !literal.is_empty() {
+                tokens.push(Token::Literal(std::mem::take(&mut literal)));
+            }
+            tokens.push(token);
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        Ok(Format { tokens })
+    }
+}
+
+impl Format {
+    /// Whether name of instance is part of format, so it does not need to be prepended
+    pub fn has_name(&self) -> bool {
+        self.tokens.contains(&Token::Name)
+    }
+
+    /// Renders status line, progress bar takes all space left to given width
+    pub fn render(&self, stats: &Stats, name: Option<&str>, width: usize) -> String {
+        let fraction = stats.fraction();
+
+        let mut parts = Vec::with_capacity(self.tokens.len());
+        let mut bars = 0;
+        for token in &self.tokens {
+            let part = match token {
+                Token::Literal(s) => s.clone(),
+                Token::Name => name.unwrap_or_default().to_string(),
+                Token::Bytes => stats.unit.amount(stats.count),
+                Token::Elapsed => format_duration(stats.elapsed),
+                Token::Rate => stats.unit.rate(stats.rate),
+                Token::Average => stats.unit.rate(stats.average),
+                Token::Eta => match stats.eta() {
+                    Some(eta) => format_duration(eta),
+                    None => "--:--:--".to_string(),
+                },
+                Token::Percent => match fraction {
+                    Some(fraction) => format!("{:3.0}%", fraction * 100.0),
+                    None => "  ?%".to_string(),
+                },
+                Token::Stall => match stats.stalled() {
+                    Some(idle) => format!("stalled {}", format_duration(idle)),
+                    None => String::new(),
+                },
+                Token::Bar => {
+                    bars += 1;
+                    String::new()
+                }
+            };
+            parts.push(part);
+        }
+
+        let used: usize = parts.iter().map(|p| p.chars().count()).sum();
+        // keep one column free, so cursor does not wrap to the next line
+        if let Some(bar_width) = width.saturating_sub(used + 1).checked_div(bars) {
+            for (part, token) in parts.iter_mut().zip(&self.tokens) {
+                if let Token::Bar = token {
+                    *part = bar(fraction, bar_width);
+                }
+            }
+        }
+
+        parts.concat()
+    }
+}
+
+/// Progress bar including brackets, empty if total is not known
+fn bar(fraction: Option<f64>, width: usize) -> String {
+    let fraction = match fraction {
+        Some(fraction) if width > 2 => fraction,
+        _ => return String::new(),
+    };
+    let inner = width - 2;
+    let filled = (inner as f64 * fraction) as usize;
+    let mut bar = String::with_capacity(width);
+    bar.push('[');
+    bar.push_str(&"=".repeat(filled));
+    if filled < inner {
+        bar.push('>');
+        bar.push_str(&" ".repeat(inner - filled - 1));
+    }
+    bar.push(']');
+    bar
+}
+
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Local time as YYYY-MM-DD HH:MM:SS
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return secs.to_string();
+    }
+    let mut buf = [0u8; 32];
+    let len = unsafe {
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            c"%Y-%m-%d %H:%M:%S".as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_of(count: u64, total: Option<u64>) -> Stats {
+        Stats {
+            count,
+            delta: 0,
+            unit: Unit::Bytes {
+                prefixes: Prefixes::Iec,
+                bits: false,
+            },
+            total,
+            elapsed: Duration::from_secs(65),
+            idle: Duration::ZERO,
+            rate: 1024.0,
+            average: 512.0,
+            peak: 1024.0,
+        }
+    }
+
+    fn render(format: &str, stats: &Stats, width: usize) -> String {
+        format
+            .parse::<Format>()
+            .unwrap()
+            .render(stats, Some("src"), width)
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("%x".parse::<Format>().is_err());
+        assert!("abc %".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn render_tokens() {
+        let stats = stats_of(512, Some(2048));
+        assert_eq!(render("%b", &stats, 80), "512 B");
+        assert_eq!(render("%t", &stats, 80), "0:01:05");
+        assert_eq!(render("%r / %a", &stats, 80), "1.00 KiB/s / 512 B/s");
+        assert_eq!(render("%p", &stats, 80), " 25%");
+        assert_eq!(render("%e", &stats, 80), "0:00:03");
+        assert_eq!(render("[%N] 100%%", &stats, 80), "[src] 100%");
+        assert_eq!(render("%s", &stats, 80), "");
+    }
+
+    #[test]
+    fn render_without_total() {
+        let stats = stats_of(512, None);
+        assert_eq!(render("%p %e", &stats, 80), "  ?% --:--:--");
+        assert_eq!(render("a%Bb", &stats, 80), "ab");
+    }
+
+    #[test]
+    fn bar_fills_rest_of_width() {
+        let stats = stats_of(512, Some(1024));
+        // one column is kept free
+        assert_eq!(render("%B", &stats, 12), "[====>    ]");
+        assert_eq!(render("ab %B", &stats, 15), "ab [====>    ]");
+        let done = stats_of(1024, Some(1024));
+        assert_eq!(render("%B", &done, 12), "[=========]");
+    }
+
+    #[test]
+    fn bars_share_width() {
+        let stats = stats_of(0, Some(1024));
+        assert_eq!(render("%B%B", &stats, 9), "[> ][> ]");
+    }
+
+    #[test]
+    fn bar_too_narrow() {
+        let stats = stats_of(512, Some(1024));
+        assert_eq!(render("%B", &stats, 3), "");
+        assert_eq!(render("long text %B", &stats, 5), "long text ");
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    format::Unit,
+    reporter::{ExitReason, Stats},
+};
+
+/// Writes progress as newline delimited JSON objects
+pub struct JsonWriter {
+    out: Box<dyn Write + Send>,
+    name: Option<String>,
+}
+
+impl JsonWriter {
+    pub fn new(out: Box<dyn Write + Send>, name: Option<String>) -> Self {
+        JsonWriter { out, name }
+    }
+
+    pub fn progress(&mut self, stats: &Stats, engine: &str) -> io::Result<()> {
+        let mut obj = self.object(stats);
+        obj.integer("delta", stats.delta);
+        obj.number("rate", stats.rate);
+        obj.number("average", stats.average);
+        obj.optional_number("eta", stats.eta().map(|eta| eta.as_secs_f64()));
+        obj.optional_number("percent", stats.fraction().map(|f| f * 100.0));
+        obj.number("idle", stats.idle.as_secs_f64());
+        obj.string("engine", engine);
+        obj.string("state", "running");
+        self.write(obj)
+    }
+
+    pub fn summary(&mut self, stats: &Stats, engine: &str, reason: &ExitReason) -> io::Result<()> {
+        let mut obj = self.object(stats);
+        obj.number("elapsed", stats.elapsed.as_secs_f64());
+        obj.number("average", stats.average);
+        obj.number("peak", stats.peak.max(stats.average));
+        obj.optional_number("percent", stats.fraction().map(|f| f * 100.0));
+        obj.string("engine", engine);
+        match reason {
+            ExitReason::Eof => {
+                obj.string("state", "finished");
+                obj.string("reason", "eof");
+            }
+            ExitReason::Error(e) => {
+                obj.string("state", "failed");
+                obj.string("reason", "error");
+                obj.string("error", e);
+            }
+            ExitReason::Stalled(_) => {
+                obj.string("state", "failed");
+                obj.string("reason", "stalled");
+                obj.string("error", &reason.to_string());
+            }
+            ExitReason::TooSlow { .. } => {
+                obj.string("state", "failed");
+                obj.string("reason", "too-slow");
+                obj.string("error", &reason.to_string());
+            }
+            ExitReason::Interrupted => {
+                obj.string("state", "failed");
+                obj.string("reason", "interrupted");
+            }
+        }
+        self.write(obj)
+    }
+
+    /// Object with fields common to all records
+    fn object(&self, stats: &Stats) -> JsonObject {
+        let mut obj = JsonObject::new();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        obj.number("timestamp", timestamp);
+        if let Some(ref name) = self.name {
+            obj.string("name", name);
+        }
+        let count_key = match stats.unit {
+            Unit::Bytes { .. } => "bytes",
+            Unit::Lines => "lines",
+        };
+        obj.integer(count_key, stats.count);
+        obj.optional_integer("total", stats.total);
+        obj
+    }
+
+    fn write(&mut self, obj: JsonObject) -> io::Result<()> {
+        writeln!(self.out, "{}", obj.finish())?;
+        self.out.flush()
+    }
+}
+
+struct JsonObject {
+    buf: String,
+}
+
+impl JsonObject {
+    fn new() -> Self {
+        JsonObject {
+            buf: String::from("{"),
+        }
+    }
+
+    fn key(&mut self, key: &str) {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        self.buf.push_str(&escape(key));
+        self.buf.push(':');
+    }
+
+    fn number(&mut self, key: &str, value: f64) {
+        self.key(key);
+        if !value.is_finite() {
+            self.buf.push_str("null");
+        } else if value.fract() == 0.0 && value.abs() < 1e15 {
+            self.buf.push_str(&format!("{}", value as i64));
+        } else {
+            self.buf.push_str(&format!("{:.3}", value));
+        }
+    }
+
+    /// Counts are written exactly, f64 would lose precision above 2^53
+    fn integer(&mut self, key: &str, value: u64) {
+        self.key(key);
+        self.buf.push_str(&value.to_string());
+    }
+
+    fn optional_integer(&mut self, key: &str, value: Option<u64>) {
+        match value {
+            Some(value) => self.integer(key, value),
+            None => {
+                self.key(key);
+                self.buf.push_str("null");
+            }
+        }
+    }
+
+    fn optional_number(&mut self, key: &str, value: Option<f64>) {
+        match value {
+            Some(value) => self.number(key, value),
+            None => {
+                self.key(key);
+                self.buf.push_str("null");
+            }
+        }
+    }
+
+    fn string(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.buf.push_str(&escape(value));
+    }
+
+    fn finish(mut self) -> String {
+        self.buf.push('}');
+        self.buf
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_special_characters() {
+        assert_eq!(escape("plain"), "\"plain\"");
+        assert_eq!(escape("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(escape("\n\r\t"), "\"\\n\\r\\t\"");
+        assert_eq!(escape("\u{1}"), "\"\\u0001\"");
+        assert_eq!(escape("žluť"), "\"žluť\"");
+    }
+
+    #[test]
+    fn numbers() {
+        let mut obj = JsonObject::new();
+        obj.number("a", 5.0);
+        obj.number("b", 1.23456);
+        obj.number("c", f64::NAN);
+        obj.optional_number("d", None);
+        assert_eq!(obj.finish(), r#"{"a":5,"b":1.235,"c":null,"d":null}"#);
+    }
+
+    #[test]
+    fn integers_are_exact() {
+        let mut obj = JsonObject::new();
+        obj.integer("a", u64::MAX);
+        obj.integer("b", (1 << 53) + 1);
+        obj.optional_integer("c", Some(1_000_000_000_000_000));
+        obj.optional_integer("d", None);
+        assert_eq!(
+            obj.finish(),
+            r#"{"a":18446744073709551615,"b":9007199254740993,"c":1000000000000000,"d":null}"#
+        );
+    }
+
+    #[test]
+    fn string_values() {
+        let mut obj = JsonObject::new();
+        obj.string("name", "a \"b\"");
+        assert_eq!(obj.finish(), r#"{"name":"a \"b\""}"#);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    sync::{atomic, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Token bucket limiting transfer rate
+///
+/// Bucket can go into debt, when more data were transferred than available tokens,
+/// next transfer then waits until debt is paid, so long term rate is kept exactly.
+///
+/// Rate is shared, so it can be changed while transfer is running.
+pub struct RateLimiter {
+    /// bytes per second, 0 means unlimited
+    rate: Arc<atomic::AtomicU64>,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Arc<atomic::AtomicU64>, burst: u64) -> Self {
+        let burst = burst.max(1) as f64;
+        RateLimiter {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.rate() > 0.0
+    }
+
+    fn rate(&self) -> f64 {
+        self.rate.load(atomic::Ordering::Relaxed) as f64
+    }
+
+    /// Waits until tokens are available and returns how many bytes can be transferred now (at most max)
+    pub fn next_chunk(&mut self, max: usize) -> usize {
+        let mut rate = self.rate();
+        if rate <= 0.0 {
+            return max;
+        }
+
+        self.refill(rate);
+        while self.tokens < 1.0 {
+            // sleep in short steps, so change of rate is picked up quickly
+            let wait = ((1.0 - self.tokens) / rate).min(MAX_WAIT.as_secs_f64());
+            thread::sleep(Duration::from_secs_f64(wait));
+            rate = self.rate();
+            if rate <= 0.0 {
+                return max;
+            }
+            self.refill(rate);
+        }
+
+        // burst is at least 1
+        max.min(self.burst as usize)
+    }
+
+    /// Takes tokens for actually transferred bytes
+    pub fn consume(&mut self, bytes: usize) {
+        if self.is_limited() {
+            self.tokens -= bytes as f64;
+        }
+    }
+
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate).min(self.burst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: u64, burst: u64) -> RateLimiter {
+        RateLimiter::new(Arc::new(atomic::AtomicU64::new(rate)), burst)
+    }
+
+    #[test]
+    fn unlimited_returns_whole_chunk() {
+        let mut limiter = limiter(0, 10);
+        assert!(!limiter.is_limited());
+        assert_eq!(limiter.next_chunk(65536), 65536);
+        limiter.consume(65536);
+        assert_eq!(limiter.next_chunk(65536), 65536);
+    }
+
+    #[test]
+    fn chunk_is_limited_by_burst() {
+        let mut limiter = limiter(1_000_000, 1000);
+        assert!(limiter.is_limited());
+        assert_eq!(limiter.next_chunk(65536), 1000);
+        assert_eq!(limiter.next_chunk(100), 100);
+    }
+
+    #[test]
+    fn chunk_never_exceeds_max() {
+        let mut limiter = limiter(1_000_000, 1000);
+        assert_eq!(limiter.next_chunk(0), 0);
+        assert_eq!(limiter.next_chunk(1), 1);
+    }
+
+    #[test]
+    fn debt_is_paid_by_waiting() {
+        // 10 ms worth of data over burst
+        let mut limiter = limiter(100_000, 1000);
+        limiter.consume(2000);
+        let start = Instant::now();
+        assert!(limiter.next_chunk(1000) >= 1);
+        assert!(start.elapsed() >= Duration::from_millis(9));
+    }
+
+    #[test]
+    fn rate_change_is_picked_up() {
+        let rate = Arc::new(atomic::AtomicU64::new(1));
+        let mut limiter = RateLimiter::new(rate.clone(), 1000);
+        limiter.consume(1_000_000);
+        rate.store(0, atomic::Ordering::Relaxed);
+        let start = Instant::now();
+        assert_eq!(limiter.next_chunk(4096), 4096);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
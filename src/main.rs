@@ -1,140 +1,525 @@
 use std::{
-    io,
-    os::fd::{AsRawFd, RawFd},
-    sync::{atomic, Arc},
-    thread::{self, JoinHandle},
+    io::{self, Read, Write},
+    os::fd::AsRawFd,
+    sync::{atomic, Arc, Mutex},
+    time::Duration,
 };
 
 use clap::Parser;
+use copy::{input_size, is_unsupported, select_engine, Engine, EngineChoice, FdKind};
+use format::{Format, Prefixes, Unit, DEFAULT_FORMAT, DEFAULT_FORMAT_WITH_TOTAL};
+use limit::RateLimiter;
+use output::Target;
+use remote::{send_rate_limit, ControlChannel};
+use reporter::{
+    ExitReason, MinRate, Mode, Reporter, Style, DEFAULT_INTERVAL, DEFAULT_LOG_INTERVAL,
+};
+
+mod copy;
+mod format;
+mod json;
+mod limit;
+mod output;
+mod remote;
+mod reporter;
+mod signal;
+mod terminal;
+
+// shorter update period would just burn CPU
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
 
 type MainResult = std::result::Result<(), Box<dyn std::error::Error + 'static>>;
 
-struct Reporter {
-    counter: Arc<atomic::AtomicU64>,
-}
-impl Reporter {
-    fn new(counter: Arc<atomic::AtomicU64>) -> Self {
-        Self { counter }
+/// Parses size with optional binary suffix K, M, G, T, P (e.g. 1.5G)
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix('B'))
+        .unwrap_or(s);
+    let (num, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') => (&s[..s.len() - 1], 1 << 40),
+        Some('P') => (&s[..s.len() - 1], 1 << 50),
+        _ => (s, 1),
+    };
+    let num: f64 = num
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size: {}", s))?;
+    if !num.is_finite() || num < 0.0 {
+        return Err(format!("invalid size: {}", s));
     }
+    Ok((num * multiplier as f64) as u64)
+}
 
-    fn run(self) -> JoinHandle<()> {
-        let counter = self.counter;
-
-        thread::spawn(move || {
-            let mut last = 0;
-            let mut secs = 1;
-            loop {
-                let count = counter.load(atomic::Ordering::Relaxed);
-
-                if count != last {
-                    let mb = (count - last) as f64 / 1024.0 / 1024.0 / secs as f64;
-                    eprint!("\r{:0.3} MiB/s", mb);
-                    last = count;
-                    secs = 1;
-                } else {
-                    secs += 1;
-                }
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number of seconds: {}", s))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("invalid number of seconds: {}", e))
+}
 
-                std::thread::sleep(std::time::Duration::from_millis(1000));
-            }
-        })
+fn parse_positive_seconds(s: &str) -> Result<Duration, String> {
+    match parse_seconds(s)? {
+        d if d.is_zero() => Err("must be longer than zero".to_string()),
+        d => Ok(d),
     }
 }
 
 #[derive(Parser, Debug)]
 struct Options {
-    #[arg(short = 'C', long, help = "Don't use splice(2) for copying")]
-    do_not_use_splice: bool,
-    #[arg(short = 's', long, default_value = "32", help = "chunk size in KiB")]
+    #[arg(
+        short = 'e',
+        long,
+        value_enum,
+        default_value = "auto",
+        help = "Copy engine, auto selects fastest one based on input and output types"
+    )]
+    engine: EngineChoice,
+    #[arg(
+        short = 's',
+        long,
+        default_value = "32",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "chunk size in KiB"
+    )]
     chunk_size_kb: usize,
+    #[arg(
+        short = 'S',
+        long,
+        value_parser = parse_size,
+        help = "Expected total size (with optional suffix K, M, G, T, P), enables progress bar and ETA, detected automatically if input is file or block device, in line mode it is number of lines"
+    )]
+    size: Option<u64>,
+    #[arg(
+        short = 'L',
+        long,
+        value_parser = parse_size,
+        help = "Limit transfer rate to given bytes per second (with optional suffix K, M, G, T, P), 0 means unlimited"
+    )]
+    rate_limit: Option<u64>,
+    #[arg(
+        short = 'R',
+        long,
+        value_name = "PID",
+        requires = "rate_limit",
+        help = "Change rate limit of running rpv process with given PID, instead of copying"
+    )]
+    remote: Option<u32>,
+    #[arg(
+        long,
+        value_parser = parse_size,
+        help = "Burst size for rate limit, maximum bytes transferred at once [default: chunk size]"
+    )]
+    burst: Option<u64>,
+    #[arg(
+        short = 'i',
+        long,
+        value_name = "SECS",
+        value_parser = parse_seconds,
+        help = "Update period of status line, numeric output, log lines and JSON stream, e.g. 0.25 [default: 1, 10 in log style]"
+    )]
+    interval: Option<Duration>,
+    #[arg(
+        short = 'w',
+        long,
+        default_value = "5",
+        value_name = "SECS",
+        value_parser = parse_seconds,
+        help = "Time window for exponential moving average of current rate, 0 shows rate of last interval"
+    )]
+    average_window: Duration,
+    #[arg(
+        short = 'F',
+        long,
+        help = "Status line format: %b bytes (or lines), %t elapsed time, %r current rate, %a average rate, %e ETA, %p percentage, %B progress bar, %s time since last data if stalled, %N name of instance, %% percent sign"
+    )]
+    format: Option<Format>,
+    #[arg(
+        short = 'n',
+        long,
+        help = "Print integer percentage (or bytes count, if size is unknown) per line instead of status line, useful for dialog --gauge"
+    )]
+    numeric: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "auto",
+        help = "Progress style, auto uses status line on terminal and timestamped log lines otherwise"
+    )]
+    style: Style,
+    #[arg(
+        short = 'l',
+        long,
+        help = "Count lines instead of bytes, works only with rw, splice and pipe-splice engines"
+    )]
+    line_mode: bool,
+    #[arg(
+        short = '0',
+        long,
+        help = "Count NUL delimited records instead of bytes (e.g. output of find -print0), implies --line-mode"
+    )]
+    null: bool,
+    #[arg(
+        long,
+        help = "Scale bytes by powers of 1000 (kB, MB, ...) instead of 1024 (KiB, MiB, ...)"
+    )]
+    si: bool,
+    #[arg(
+        short = 'b',
+        long,
+        help = "Show rates in bits per second, e.g. for comparison with link speed"
+    )]
+    bits: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = parse_seconds,
+        help = "Warn, when no data were transferred for given time"
+    )]
+    stall_warning: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = parse_seconds,
+        help = "Abort with exit code 3, when no data were transferred for given time"
+    )]
+    idle_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_parser = parse_size,
+        help = "Abort with exit code 4, when rate over --min-rate-window drops below given bytes (or lines) per second (with optional suffix K, M, G, T, P)"
+    )]
+    min_rate: Option<u64>,
+    #[arg(
+        long,
+        default_value = "10",
+        value_name = "SECS",
+        value_parser = parse_positive_seconds,
+        help = "Sliding window, over which rate is compared to --min-rate"
+    )]
+    min_rate_window: Duration,
+    #[arg(
+        long,
+        default_value = "5",
+        value_name = "SECS",
+        value_parser = parse_seconds,
+        help = "Time after start, when --min-rate is not checked yet"
+    )]
+    min_rate_grace: Duration,
+    #[arg(
+        short = 'c',
+        long,
+        help = "Use own terminal row, so several rpv instances in one pipeline are displayed stacked"
+    )]
+    cursor: bool,
+    #[arg(
+        short = 'N',
+        long,
+        help = "Name of this instance, prepended to status line (unless format contains %N), log lines and summary, and included in JSON stream"
+    )]
+    name: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH|fd:N",
+        help = "Write progress as newline delimited JSON objects to given file or file descriptor"
+    )]
+    json: Option<Target>,
+    #[arg(
+        short = 'd',
+        long,
+        value_name = "PATH|fd:N",
+        help = "Write progress display to given file or file descriptor instead of stderr, e.g. /dev/tty"
+    )]
+    display: Option<Target>,
+    #[arg(short = 'v', long, help = "Print additional information to stderr")]
+    verbose: bool,
+}
+
+fn main() {
+    // returning error from main would print it with Debug formatting, which is not meant for users
+    if let Err(e) = run(Options::parse()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
 }
 
-fn main() -> MainResult {
-    let args = Options::parse();
-    let input = io::stdin().lock();
-    let output = io::stdout().lock();
+fn run(args: Options) -> MainResult {
+    if let Some(pid) = args.remote {
+        // rate_limit is required by clap
+        send_rate_limit(pid, args.rate_limit.unwrap_or(0))?;
+        return Ok(());
+    }
 
-    let counter = Arc::new(atomic::AtomicU64::new(0));
-    let reporter = Reporter::new(counter.clone());
-    reporter.run();
+    signal::watch_requests();
 
-    let chunk_size = args.chunk_size_kb * 1024;
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
 
-    if args.do_not_use_splice {
-        rw_copy(input, output, counter, chunk_size)
+    let delimiter = if args.null {
+        Some(0)
+    } else if args.line_mode {
+        Some(b'\n')
     } else {
-        splice_copy(input, output, counter, chunk_size)
+        None
+    };
+    if delimiter.is_some()
+        && !matches!(
+            args.engine,
+            EngineChoice::Auto | EngineChoice::Rw | EngineChoice::Splice | EngineChoice::PipeSplice
+        )
+    {
+        return Err("line mode is supported only by rw, splice and pipe-splice engines".into());
     }
-}
+    let unit = if delimiter.is_some() {
+        Unit::Lines
+    } else {
+        Unit::Bytes {
+            prefixes: if args.si { Prefixes::Si } else { Prefixes::Iec },
+            bits: args.bits,
+        }
+    };
 
-fn splice(fd_in: RawFd, fd_out: RawFd, size: usize) -> Result<usize, io::Error> {
-    let res = unsafe {
-        libc::splice(
-            fd_in,
-            std::ptr::null_mut::<libc::loff_t>(),
-            fd_out,
-            std::ptr::null_mut::<libc::loff_t>(),
-            size,
-            0,
-        )
+    // file size is in bytes, so it cannot be used as total in line mode
+    let size = match delimiter {
+        Some(_) => args.size,
+        None => args.size.or_else(|| input_size(input.as_raw_fd())),
     };
-    if res < 0 {
-        Err(io::Error::last_os_error())
+
+    let counter = Arc::new(atomic::AtomicU64::new(0));
+    let format = match args.format {
+        Some(format) => format,
+        None if size.is_some() => DEFAULT_FORMAT_WITH_TOTAL.parse()?,
+        None => DEFAULT_FORMAT.parse()?,
+    };
+    let chunk_size = args.chunk_size_kb * 1024;
+
+    let mut engine = match args.engine.engine() {
+        Some(engine) => engine,
+        None if delimiter.is_some() => {
+            // tee(2) can duplicate data for counting only from pipe
+            let (engine, reason) = if FdKind::of(input.as_raw_fd()) == FdKind::Fifo {
+                (
+                    Engine::Splice,
+                    "input is pipe, so lines can be counted in data duplicated by tee",
+                )
+            } else {
+                (
+                    Engine::PipeSplice,
+                    "lines can be counted in data duplicated by tee from internal pipe",
+                )
+            };
+            if args.verbose {
+                eprintln!("Selected {} engine: {}", engine, reason);
+            }
+            engine
+        }
+        None => {
+            let kind_in = FdKind::of(input.as_raw_fd());
+            let kind_out = FdKind::of(output.as_raw_fd());
+            let (engine, reason) = select_engine(kind_in, kind_out);
+            if args.verbose {
+                eprintln!(
+                    "Input is {}, output is {}, selected {} engine: {}",
+                    kind_in, kind_out, engine, reason
+                );
+            }
+            engine
+        }
+    };
+
+    let rate_limit = Arc::new(atomic::AtomicU64::new(args.rate_limit.unwrap_or(0)));
+    let control = match ControlChannel::listen(rate_limit.clone()) {
+        Ok(control) => Some(control),
+        Err(e) => {
+            if args.verbose {
+                eprintln!(
+                    "Cannot create control channel, rate limit cannot be changed: {}",
+                    e
+                );
+            }
+            None
+        }
+    };
+    let mut limiter = RateLimiter::new(rate_limit, args.burst.unwrap_or(chunk_size as u64));
+    // reflink cannot be throttled, it copies whole file at once
+    if limiter.is_limited()
+        && args.engine == EngineChoice::Auto
+        && matches!(engine, Engine::Reflink)
+    {
+        engine = Engine::CopyFileRange;
+    }
+
+    let json = args.json.as_ref().map(|target| target.open()).transpose()?;
+    let display = args
+        .display
+        .as_ref()
+        .map(|target| target.open())
+        .transpose()?;
+    let display_fd = display
+        .as_ref()
+        .map_or(libc::STDERR_FILENO, |file| file.as_raw_fd());
+    let style = match args.style {
+        Style::Auto if unsafe { libc::isatty(display_fd) } == 1 => Style::Status,
+        Style::Auto => Style::Log,
+        style => style,
+    };
+    let mode = if args.numeric {
+        Mode::Numeric
+    } else if style == Style::Log {
+        Mode::Log
     } else {
-        Ok(res as usize)
+        terminal::watch_resize();
+        Mode::Status
+    };
+    // log is kept small by default
+    let interval = match mode {
+        Mode::Log => args.interval.unwrap_or(DEFAULT_LOG_INTERVAL),
+        _ => args.interval.unwrap_or(DEFAULT_INTERVAL),
+    };
+    let engine = Arc::new(Mutex::new(engine));
+    let reporter = Reporter::new(counter.clone(), size, format, engine.clone())
+        .with_interval(interval.max(MIN_INTERVAL))
+        .with_average_window(args.average_window)
+        .with_mode(mode)
+        .with_unit(unit)
+        .with_cursor(args.cursor)
+        .with_name(args.name)
+        .with_json(json)
+        .with_display(display)
+        .with_stall_warning(args.stall_warning)
+        .with_idle_timeout(args.idle_timeout)
+        .with_min_rate(
+            args.min_rate
+                .map(|rate| MinRate::new(rate, args.min_rate_window, args.min_rate_grace)),
+        )
+        // copying is blocked in syscall, so abort exits from reporter thread and must clean up itself
+        .with_on_abort(move || drop(control))
+        .run();
+
+    let res = copy_with_fallback(
+        &engine,
+        args.engine == EngineChoice::Auto,
+        args.verbose,
+        &mut input,
+        &mut output,
+        counter,
+        chunk_size,
+        &mut limiter,
+        delimiter,
+    );
+    reporter.finish(match res {
+        Ok(()) => ExitReason::Eof,
+        Err(ref e) => ExitReason::Error(e.to_string()),
+    });
+
+    if args.verbose {
+        eprintln!("Copied using {} engine", engine.lock().unwrap());
     }
+
+    Ok(res?)
 }
 
-fn splice_copy<R, W>(
-    input: R,
-    output: W,
+/// Copies input to output, if engine is not supported and fallback is allowed, continues with next engine
+///
+/// Engine is updated, when falling back, so it always holds the current one.
+#[allow(clippy::too_many_arguments)]
+fn copy_with_fallback<R, W>(
+    current_engine: &Mutex<Engine>,
+    fallback: bool,
+    verbose: bool,
+    input: &mut R,
+    output: &mut W,
     counter: Arc<atomic::AtomicU64>,
     chunk_size: usize,
-) -> MainResult
+    limiter: &mut RateLimiter,
+    delimiter: Option<u8>,
+) -> io::Result<()>
 where
-    R: AsRawFd,
-    W: AsRawFd,
+    R: AsRawFd + Read,
+    W: AsRawFd + Write,
 {
-    let fd_in = input.as_raw_fd();
-    let fd_out = output.as_raw_fd();
-
+    let mut engine = *current_engine.lock().unwrap();
     loop {
-        let written = splice(fd_in, fd_out, chunk_size)?;
-        if written == 0 {
-            break;
+        match engine.copy(
+            input,
+            output,
+            counter.clone(),
+            chunk_size,
+            limiter,
+            delimiter,
+        ) {
+            Ok(()) => return Ok(()),
+            // explicitly requested engine should fail rather than silently change
+            Err(e) if is_unsupported(&e) && fallback => match engine.fallback() {
+                Some(next) => {
+                    if verbose {
+                        eprintln!(
+                            "{} not supported for given input/output ({}), falling back to {}",
+                            engine, e, next
+                        );
+                    }
+                    engine = next;
+                    *current_engine.lock().unwrap() = next;
+                }
+                None => return Err(e),
+            },
+            Err(e) => return Err(e),
         }
-        counter.fetch_add(written as u64, atomic::Ordering::Relaxed);
     }
-
-    Ok(())
 }
 
-fn rw_copy<R, W>(
-    mut input: R,
-    mut output: W,
-    counter: Arc<atomic::AtomicU64>,
-    chunk_size: usize,
-) -> MainResult
-where
-    R: io::Read,
-    W: io::Write,
-{
-    let mut buffer = vec![0u8; chunk_size];
-    Ok(loop {
-        let mut read = input.read(&mut buffer)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if read == 0 {
-            break;
-        }
+    #[test]
+    fn parse_size_plain() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size(" 1234 "), Ok(1234));
+    }
 
-        let mut offset = 0;
+    #[test]
+    fn parse_size_suffixes() {
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert_eq!(parse_size("1P"), Ok(1 << 50));
+        assert_eq!(parse_size("4KiB"), Ok(4096));
+        assert_eq!(parse_size("4KB"), Ok(4096));
+        assert_eq!(parse_size("100B"), Ok(100));
+    }
 
-        while read > 0 {
-            let written = output.write(&buffer[offset..offset + read])?;
-            offset += written;
-            read -= written;
+    #[test]
+    fn parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("inf").is_err());
+    }
 
-            counter.fetch_add(written as u64, atomic::Ordering::Relaxed);
-        }
-    })
+    #[test]
+    fn parse_seconds_values() {
+        assert_eq!(parse_seconds("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_seconds("0.25"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_seconds("0"), Ok(Duration::ZERO));
+        assert!(parse_seconds("-1").is_err());
+        assert!(parse_seconds("abc").is_err());
+    }
+
+    #[test]
+    fn parse_positive_seconds_rejects_zero() {
+        assert_eq!(
+            parse_positive_seconds("0.5"),
+            Ok(Duration::from_millis(500))
+        );
+        assert!(parse_positive_seconds("0").is_err());
+    }
 }
@@ -0,0 +1,48 @@
+use std::{
+    fs::File,
+    io,
+    os::fd::{FromRawFd, RawFd},
+    path::PathBuf,
+};
+
+/// Where output goes, given as path or as `fd:N` for already open file descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Fd(RawFd),
+    Path(PathBuf),
+}
+
+impl std::str::FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("fd:") {
+            Some(fd) => match fd.parse() {
+                // stdin and stdout carry copied data
+                Ok(0 | 1) => Err(format!("file descriptor {} is used for data", fd)),
+                Ok(fd) => Ok(Target::Fd(fd)),
+                Err(_) => Err(format!("invalid file descriptor: {}", fd)),
+            },
+            None if s.is_empty() => Err("empty path".to_string()),
+            None => Ok(Target::Path(PathBuf::from(s))),
+        }
+    }
+}
+
+impl Target {
+    /// Opens target for writing, file is created or truncated
+    ///
+    /// File descriptor is duplicated, so the original one (e.g. stderr) stays open, when returned file is closed.
+    pub fn open(&self) -> io::Result<File> {
+        match self {
+            Target::Fd(fd) => {
+                let dup = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if dup < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(unsafe { File::from_raw_fd(dup) })
+            }
+            Target::Path(path) => File::create(path),
+        }
+    }
+}
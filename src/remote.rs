@@ -0,0 +1,111 @@
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, OpenOptionsExt},
+    },
+    path::PathBuf,
+    sync::{atomic, Arc},
+    thread,
+};
+
+const RATE_LIMIT_CMD: &str = "rate-limit";
+
+/// Directory for control and lock files
+pub fn runtime_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Path of control FIFO for rpv process with given PID
+fn control_path(pid: u32) -> PathBuf {
+    runtime_dir().join(format!("rpv-{}.ctl", pid))
+}
+
+/// Control channel of running process, FIFO is removed when dropped
+pub struct ControlChannel {
+    path: PathBuf,
+}
+
+impl ControlChannel {
+    /// Creates control FIFO for this process and starts thread reading commands from it
+    pub fn listen(rate_limit: Arc<atomic::AtomicU64>) -> io::Result<Self> {
+        let path = control_path(std::process::id());
+        // might be left over from crashed process with same PID
+        let _ = fs::remove_file(&path);
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // opened for writing too, so reads do not end with EOF when remote closes its end
+        let fifo = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+        // path might have been replaced between mkfifo and open
+        if !is_own_fifo(&fifo)? {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not own FIFO", path.display()),
+            ));
+        }
+
+        thread::spawn(move || {
+            for line in BufReader::new(fifo).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let mut parts = line.split_whitespace();
+                if let (Some(RATE_LIMIT_CMD), Some(value)) = (parts.next(), parts.next()) {
+                    if let Ok(rate) = value.parse() {
+                        rate_limit.store(rate, atomic::Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+
+        Ok(ControlChannel { path })
+    }
+}
+
+impl Drop for ControlChannel {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Whether opened file is FIFO owned by current user
+fn is_own_fifo(file: &File) -> io::Result<bool> {
+    let meta = file.metadata()?;
+    Ok(meta.file_type().is_fifo() && meta.uid() == unsafe { libc::geteuid() })
+}
+
+/// Sends new rate limit (bytes per second, 0 for unlimited) to running rpv process
+pub fn send_rate_limit(pid: u32, rate: u64) -> io::Result<()> {
+    let path = control_path(pid);
+    let no_process = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no running rpv process with PID {}", pid),
+        )
+    };
+    // non-blocking open fails with ENXIO, if there is no reader,
+    // symlink or file of someone else might be planted in shared /tmp, so they are refused
+    let mut fifo = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(&path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ENXIO | libc::ENOENT | libc::ELOOP) => no_process(),
+            _ => e,
+        })?;
+    if !is_own_fifo(&fifo)? {
+        return Err(no_process());
+    }
+    fifo.write_all(format!("{} {}\n", RATE_LIMIT_CMD, rate).as_bytes())
+}
@@ -0,0 +1,812 @@
+use clap::ValueEnum;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Write},
+    os::fd::{AsRawFd, RawFd},
+    sync::{atomic, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    copy::Engine,
+    format::{format_duration, format_timestamp, Format, Prefixes, Unit},
+    json::JsonWriter,
+    signal,
+    terminal::{CursorSlot, StatusLine},
+};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(1000);
+pub const DEFAULT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// shorter samples (e.g. last one when copying ends) are too noisy for peak rate
+const MIN_PEAK_SAMPLE: f64 = 0.1;
+// shorter pauses are normal (e.g. writer waiting for more data), so they are not shown as stall
+const STALL_THRESHOLD: Duration = Duration::from_secs(2);
+// signal handler cannot wake reporter thread, so it checks for signals (and watchdog) this often
+const POLL: Duration = Duration::from_millis(100);
+/// Exit code, when transfer is aborted because of idle timeout
+pub const EXIT_STALLED: i32 = 3;
+/// Exit code, when transfer is aborted because rate dropped below minimum
+pub const EXIT_TOO_SLOW: i32 = 4;
+
+pub struct Reporter {
+    counter: Arc<atomic::AtomicU64>,
+    total: Option<u64>,
+    interval: Duration,
+    average_window: Duration,
+    format: Format,
+    mode: Mode,
+    unit: Unit,
+    cursor: bool,
+    name: Option<String>,
+    json: Option<File>,
+    display: Option<File>,
+    stall_warning: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    min_rate: Option<MinRate>,
+    on_abort: Option<Box<dyn FnOnce() + Send>>,
+    engine: Arc<Mutex<Engine>>,
+}
+
+/// How progress is displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Status line updated in place
+    Status,
+    /// Integer percentage (or byte count if total is unknown) per line, e.g. for dialog --gauge
+    Numeric,
+    /// Timestamped line per update, for logs of non-interactive runs
+    Log,
+}
+
+/// Value of --style option
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Style {
+    /// Status line on terminal, log lines otherwise
+    Auto,
+    Status,
+    Log,
+}
+
+/// Why copying ended
+#[derive(Debug, Clone)]
+pub enum ExitReason {
+    Eof,
+    Error(String),
+    /// aborted, because no data were transferred for given time
+    Stalled(Duration),
+    /// aborted, because rate over window dropped below minimum
+    TooSlow {
+        rate: f64,
+        min_rate: f64,
+        window: Duration,
+        unit: Unit,
+    },
+    /// aborted by SIGINT
+    Interrupted,
+}
+
+impl ExitReason {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitReason::Eof => 0,
+            ExitReason::Error(_) => 1,
+            ExitReason::Stalled(_) => EXIT_STALLED,
+            ExitReason::TooSlow { .. } => EXIT_TOO_SLOW,
+            ExitReason::Interrupted => 128 + libc::SIGINT,
+        }
+    }
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Eof => write!(f, "end of input"),
+            ExitReason::Error(e) => write!(f, "{}", e),
+            ExitReason::Stalled(idle) => {
+                write!(f, "no data transferred for {}", format_duration(*idle))
+            }
+            ExitReason::TooSlow {
+                rate,
+                min_rate,
+                window,
+                unit,
+            } => write!(
+                f,
+                "rate {} over last {} is below minimum {}",
+                unit.rate(*rate),
+                format_duration(*window),
+                unit.rate(*min_rate)
+            ),
+            ExitReason::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+/// Running reporter thread, which should be finished when copying is done
+pub struct ReporterHandle {
+    stop: Arc<atomic::AtomicBool>,
+    reason: Arc<Mutex<ExitReason>>,
+    thread: JoinHandle<()>,
+}
+
+impl ReporterHandle {
+    /// Stops reporter thread, which prints final summary before it ends
+    pub fn finish(self, reason: ExitReason) {
+        *self.reason.lock().unwrap() = reason;
+        self.stop.store(true, atomic::Ordering::Relaxed);
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+/// Transfer statistics, rates are in units (bytes or lines) per second
+pub struct Stats {
+    pub count: u64,
+    /// increase of count since previous sample
+    pub delta: u64,
+    pub unit: Unit,
+    pub total: Option<u64>,
+    pub elapsed: Duration,
+    /// time since count last increased
+    pub idle: Duration,
+    /// rate smoothed by exponential moving average, or rate of last interval if smoothing is off
+    pub rate: f64,
+    pub average: f64,
+    pub peak: f64,
+}
+
+impl Stats {
+    /// Transferred part of total, if total is known
+    pub fn fraction(&self) -> Option<f64> {
+        self.total.map(|total| {
+            if total > 0 {
+                (self.count as f64 / total as f64).min(1.0)
+            } else {
+                1.0
+            }
+        })
+    }
+
+    /// Time since last data, if it is long enough to be considered stall
+    pub fn stalled(&self) -> Option<Duration> {
+        (self.idle >= STALL_THRESHOLD).then_some(self.idle)
+    }
+
+    /// Estimated remaining time based on average rate
+    pub fn eta(&self) -> Option<Duration> {
+        match self.total {
+            Some(total) if self.count > 0 => {
+                let remains = total.saturating_sub(self.count) as f64 / self.average;
+                Duration::try_from_secs_f64(remains).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Minimum rate over sliding window, which is checked after grace period
+pub struct MinRate {
+    rate: f64,
+    window: Duration,
+    grace: Duration,
+    /// (elapsed, count), oldest sample is the last one not newer than start of window
+    samples: VecDeque<(Duration, u64)>,
+}
+
+impl MinRate {
+    pub fn new(rate: u64, window: Duration, grace: Duration) -> Self {
+        MinRate {
+            rate: rate as f64,
+            window,
+            grace,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Adds sample and returns rate over window, if it is below minimum
+    fn check(&mut self, elapsed: Duration, count: u64) -> Option<f64> {
+        // window starts after grace period, so slow start (e.g. disk spin-up) is not counted
+        if elapsed < self.grace {
+            return None;
+        }
+        self.samples.push_back((elapsed, count));
+        let start = elapsed.checked_sub(self.window)?;
+        while self.samples.len() > 1 && self.samples[1].0 <= start {
+            self.samples.pop_front();
+        }
+        let &(first_time, first_count) = self.samples.front()?;
+        if first_time > start {
+            // window is not full yet
+            return None;
+        }
+        let dt = (elapsed - first_time).as_secs_f64();
+        if dt <= 0.0 {
+            return None;
+        }
+        let rate = count.saturating_sub(first_count) as f64 / dt;
+        (rate < self.rate).then_some(rate)
+    }
+}
+
+/// Computes rates from counter samples taken at measured times
+struct RateMeter {
+    start: Instant,
+    last_time: Instant,
+    last_count: u64,
+    /// when count last increased
+    last_progress: Instant,
+    progress_count: u64,
+    window: f64,
+    min_peak_sample: f64,
+    ema: Option<f64>,
+    peak: f64,
+}
+
+impl RateMeter {
+    /// Samples are expected to be taken with given interval
+    fn new(window: Duration, interval: Duration) -> Self {
+        let now = Instant::now();
+        RateMeter {
+            start: now,
+            last_time: now,
+            last_count: 0,
+            last_progress: now,
+            progress_count: 0,
+            window: window.as_secs_f64(),
+            // with very short interval all samples would be too short
+            min_peak_sample: MIN_PEAK_SAMPLE.min(interval.as_secs_f64() / 2.0),
+            ema: None,
+            peak: 0.0,
+        }
+    }
+
+    /// Notes current count, without taking rate sample, so stall is detected between samples
+    fn observe(&mut self, now: Instant, count: u64) {
+        if count > self.progress_count {
+            self.progress_count = count;
+            self.last_progress = now;
+        }
+    }
+
+    /// Time since count last increased
+    fn idle(&self) -> Duration {
+        self.last_progress.elapsed()
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Adds new sample taken at given time and returns current statistics
+    fn update(&mut self, now: Instant, count: u64, total: Option<u64>, unit: Unit) -> Stats {
+        self.observe(now, count);
+        let dt = now.duration_since(self.last_time).as_secs_f64();
+        let delta = count.saturating_sub(self.last_count);
+
+        if dt > 0.0 {
+            let rate = delta as f64 / dt;
+            if dt >= self.min_peak_sample {
+                self.peak = self.peak.max(rate);
+            }
+            self.ema = Some(match self.ema {
+                Some(ema) if self.window > 0.0 => {
+                    // weight of new sample depends on its duration, so irregular intervals are handled correctly
+                    let alpha = 1.0 - (-dt / self.window).exp();
+                    ema + alpha * (rate - ema)
+                }
+                _ => rate,
+            });
+            self.last_time = now;
+            self.last_count = count;
+        }
+
+        self.stats(now, count, delta, total, unit)
+    }
+
+    /// Current statistics without taking sample, e.g. for out of band report
+    fn peek(&mut self, count: u64, total: Option<u64>, unit: Unit) -> Stats {
+        let now = Instant::now();
+        self.observe(now, count);
+        let delta = count.saturating_sub(self.last_count);
+        self.stats(now, count, delta, total, unit)
+    }
+
+    fn stats(&self, now: Instant, count: u64, delta: u64, total: Option<u64>, unit: Unit) -> Stats {
+        let elapsed = now.duration_since(self.start);
+        let average = count as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        Stats {
+            count,
+            delta,
+            unit,
+            total,
+            elapsed,
+            idle: now.duration_since(self.last_progress),
+            rate: self.ema.unwrap_or(average),
+            average,
+            peak: self.peak,
+        }
+    }
+}
+
+impl Reporter {
+    pub fn new(
+        counter: Arc<atomic::AtomicU64>,
+        total: Option<u64>,
+        format: Format,
+        engine: Arc<Mutex<Engine>>,
+    ) -> Self {
+        Self {
+            counter,
+            total,
+            interval: DEFAULT_INTERVAL,
+            average_window: Duration::ZERO,
+            format,
+            mode: Mode::Status,
+            unit: Unit::Bytes {
+                prefixes: Prefixes::Iec,
+                bits: false,
+            },
+            cursor: false,
+            name: None,
+            json: None,
+            display: None,
+            stall_warning: None,
+            idle_timeout: None,
+            min_rate: None,
+            on_abort: None,
+            engine,
+        }
+    }
+
+    /// Also write progress as JSON lines to given file
+    pub fn with_json(mut self, json: Option<File>) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// Write progress to given file (e.g. /dev/tty) instead of stderr
+    pub fn with_display(mut self, display: Option<File>) -> Self {
+        self.display = display;
+        self
+    }
+
+    /// Print warning, when no data were transferred for given time
+    pub fn with_stall_warning(mut self, stall_warning: Option<Duration>) -> Self {
+        self.stall_warning = stall_warning;
+        self
+    }
+
+    /// Abort whole process, when no data were transferred for given time
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Abort whole process, when rate drops below minimum
+    pub fn with_min_rate(mut self, min_rate: Option<MinRate>) -> Self {
+        self.min_rate = min_rate;
+        self
+    }
+
+    /// Called before process exits, when transfer is aborted
+    pub fn with_on_abort(mut self, on_abort: impl FnOnce() + Send + 'static) -> Self {
+        self.on_abort = Some(Box::new(on_abort));
+        self
+    }
+
+    /// Period of status line and numeric updates, log lines have own period
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_average_window(mut self, window: Duration) -> Self {
+        self.average_window = window;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_unit(mut self, unit: Unit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Name prepended to all output, to distinguish several instances
+    pub fn with_name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Use own terminal row, so several instances can display progress at once
+    pub fn with_cursor(mut self, cursor: bool) -> Self {
+        self.cursor = cursor;
+        self
+    }
+
+    pub fn run(self) -> ReporterHandle {
+        let counter = self.counter;
+        let total = self.total;
+        let format = self.format;
+        let mode = self.mode;
+        let unit = self.unit;
+        let cursor = self.cursor;
+        let prefix = match self.name {
+            Some(ref name) => format!("{}: ", name),
+            None => String::new(),
+        };
+        let mut json = self
+            .json
+            .map(|file| JsonWriter::new(Box::new(file), self.name.clone()));
+        let name = self.name;
+        let (mut out, out_fd): (Box<dyn Write + Send>, RawFd) = match self.display {
+            Some(file) => {
+                let fd = file.as_raw_fd();
+                (Box::new(file), fd)
+            }
+            None => (Box::new(io::stderr()), libc::STDERR_FILENO),
+        };
+        let engine = self.engine;
+        let reason = Arc::new(Mutex::new(ExitReason::Eof));
+        let exit_reason = reason.clone();
+        let stop = Arc::new(atomic::AtomicBool::new(false));
+        let stop_flag = stop.clone();
+
+        let interval = self.interval;
+        let mut meter = RateMeter::new(self.average_window, interval);
+        let stall_warning = self.stall_warning;
+        let idle_timeout = self.idle_timeout;
+        let mut min_rate = self.min_rate;
+        let on_abort = self.on_abort;
+
+        let thread = thread::spawn(move || {
+            let mut status_line = StatusLine::new(out_fd);
+            // without terminal falls back to plain status line
+            let mut slot = if cursor && mode == Mode::Status {
+                CursorSlot::acquire(out_fd, &mut out).ok()
+            } else {
+                None
+            };
+            let mut reported = false;
+            let mut warned = false;
+            loop {
+                // park can wake up spuriously, so wait until whole interval passed or stopped,
+                // watchdog is checked meanwhile, so it reacts in time regardless of interval
+                let deadline = Instant::now() + interval;
+                let mut aborted = None;
+                while !stop_flag.load(atomic::Ordering::Relaxed) {
+                    let count = counter.load(atomic::Ordering::Relaxed);
+                    meter.observe(Instant::now(), count);
+                    let idle = meter.idle();
+
+                    if signal::interrupted() {
+                        aborted = Some(ExitReason::Interrupted);
+                        break;
+                    }
+                    // answered right away, regular updates keep their cadence
+                    if signal::take_dump_request() {
+                        let line = stats_line(&meter.peek(count, total, unit));
+                        let message = format!("{}{}", prefix, line);
+                        show_message(mode, &mut out, &mut status_line, slot.as_mut(), &message);
+                    }
+
+                    if idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                        aborted = Some(ExitReason::Stalled(idle));
+                        break;
+                    }
+                    if let Some(ref mut min_rate) = min_rate {
+                        if let Some(rate) = min_rate.check(meter.elapsed(), count) {
+                            aborted = Some(ExitReason::TooSlow {
+                                rate,
+                                min_rate: min_rate.rate,
+                                window: min_rate.window,
+                                unit,
+                            });
+                            break;
+                        }
+                    }
+
+                    match stall_warning {
+                        Some(warning) if idle >= warning => {
+                            // once per stall
+                            if !warned {
+                                let message = format!(
+                                    "{}Warning: no data transferred for {}",
+                                    prefix,
+                                    format_duration(idle)
+                                );
+                                show_message(
+                                    mode,
+                                    &mut out,
+                                    &mut status_line,
+                                    slot.as_mut(),
+                                    &message,
+                                );
+                                warned = true;
+                            }
+                        }
+                        _ => warned = false,
+                    }
+
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    thread::park_timeout((deadline - now).min(POLL));
+                }
+
+                let finished = stop_flag.load(atomic::Ordering::Relaxed);
+                if let Some(ref reason) = aborted {
+                    *exit_reason.lock().unwrap() = reason.clone();
+                }
+                let stopped = finished || aborted.is_some();
+                let count = counter.load(atomic::Ordering::Relaxed);
+                let stats = meter.update(Instant::now(), count, total, unit);
+
+                if let Some(ref mut writer) = json {
+                    let engine = engine.lock().unwrap().to_string();
+                    let res = if stopped {
+                        let reason = exit_reason.lock().unwrap().clone();
+                        writer.summary(&stats, &engine, &reason)
+                    } else {
+                        writer.progress(&stats, &engine)
+                    };
+                    // e.g. reader of JSON stream went away, it should not stop copying
+                    if res.is_err() {
+                        json = None;
+                    }
+                }
+
+                // display might go away (e.g. terminal closed), it should not stop copying
+                match mode {
+                    Mode::Status if stopped && slot.is_some() => {
+                        let text =
+                            status_line.render(&format!("{}{}", prefix, summary_line(&stats)));
+                        if let Some(slot) = slot.take() {
+                            slot.release(&mut out, &text);
+                        }
+                    }
+                    Mode::Status if stopped => {
+                        let newline = if reported { "\n" } else { "" };
+                        let _ = writeln!(out, "{}{}{}", newline, prefix, summary_line(&stats));
+                    }
+                    Mode::Status => {
+                        let width = status_line.width();
+                        let line = if format.has_name() {
+                            format.render(&stats, name.as_deref(), width)
+                        } else {
+                            let width = width.saturating_sub(prefix.chars().count());
+                            format!(
+                                "{}{}",
+                                prefix,
+                                format.render(&stats, name.as_deref(), width)
+                            )
+                        };
+                        let text = status_line.render(&line);
+                        match slot {
+                            Some(ref mut slot) => slot.draw(&mut out, &text),
+                            None => {
+                                let _ = out.write_all(text.as_bytes());
+                            }
+                        }
+                        reported = true;
+                    }
+                    // final value is printed too, so gauge ends at 100%,
+                    // bare number without name, so it can be read by dialog --gauge
+                    Mode::Numeric => {
+                        let _ = writeln!(out, "{}", numeric_value(&stats));
+                    }
+                    Mode::Log => {
+                        let line = if stopped {
+                            summary_line(&stats)
+                        } else {
+                            log_line(&stats)
+                        };
+                        let time = format_timestamp(SystemTime::now());
+                        let _ = writeln!(out, "[{}] {}{}", time, prefix, line);
+                    }
+                }
+
+                if let Some(reason) = aborted {
+                    // summary is enough, when user interrupted transfer
+                    if !matches!(reason, ExitReason::Interrupted) {
+                        eprintln!("Error: {}{}", prefix, reason);
+                    }
+                    if let Some(on_abort) = on_abort {
+                        on_abort();
+                    }
+                    std::process::exit(reason.exit_code());
+                }
+
+                if stopped {
+                    break;
+                }
+            }
+        });
+
+        ReporterHandle {
+            stop,
+            reason,
+            thread,
+        }
+    }
+}
+
+/// Shows message (e.g. stall warning) on display, so it does not break status line or stacked rows
+fn show_message(
+    mode: Mode,
+    out: &mut dyn Write,
+    status_line: &mut StatusLine,
+    slot: Option<&mut CursorSlot>,
+    message: &str,
+) {
+    match (mode, slot) {
+        (Mode::Log, _) => {
+            let time = format_timestamp(SystemTime::now());
+            let _ = writeln!(out, "[{}] {}", time, message);
+        }
+        // own row is reused, message stays there until next update
+        (Mode::Status, Some(slot)) => slot.draw(out, &status_line.render(message)),
+        // message replaces status line and stays above the next one
+        (Mode::Status, None) => {
+            let _ = writeln!(out, "{}", status_line.render(message));
+        }
+        // display carries only numbers, e.g. for dialog --gauge
+        (Mode::Numeric, _) => eprintln!("{}", message),
+    }
+}
+
+fn numeric_value(stats: &Stats) -> u64 {
+    match stats.total {
+        Some(0) => 100,
+        Some(total) => (stats.count.min(total) * 100) / total,
+        None => stats.count,
+    }
+}
+
+fn log_line(stats: &Stats) -> String {
+    let mut line = format!(
+        "{}, {}",
+        stats.unit.amount(stats.count),
+        stats.unit.rate(stats.rate)
+    );
+    if let Some(fraction) = stats.fraction() {
+        line.push_str(&format!(", {:.0}%", fraction * 100.0));
+    }
+    if let Some(idle) = stats.stalled() {
+        line.push_str(&format!(", stalled {}", format_duration(idle)));
+    }
+    line
+}
+
+/// Full statistics printed on request
+fn stats_line(stats: &Stats) -> String {
+    let mut line = format!(
+        "{} copied in {}, average {}, current {}",
+        stats.unit.amount(stats.count),
+        format_duration(stats.elapsed),
+        stats.unit.rate(stats.average),
+        stats.unit.rate(stats.rate)
+    );
+    if let Some(fraction) = stats.fraction() {
+        line.push_str(&format!(", {:.1}%", fraction * 100.0));
+    }
+    line
+}
+
+fn summary_line(stats: &Stats) -> String {
+    format!(
+        "{} copied in {}, average {}, peak {}",
+        stats.unit.amount(stats.count),
+        format_duration(stats.elapsed),
+        stats.unit.rate(stats.average),
+        stats.unit.rate(stats.peak.max(stats.average))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIT: Unit = Unit::Lines;
+
+    fn at(meter: &RateMeter, secs: f64) -> Instant {
+        meter.start + Duration::from_secs_f64(secs)
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn rate_of_last_interval_without_window() {
+        let mut meter = RateMeter::new(Duration::ZERO, secs(1));
+        let stats = meter.update(at(&meter, 1.0), 1000, None, UNIT);
+        assert_close(stats.rate, 1000.0);
+        let stats = meter.update(at(&meter, 3.0), 2000, None, UNIT);
+        assert_close(stats.rate, 500.0);
+        assert_eq!(stats.delta, 1000);
+        assert_close(stats.average, 2000.0 / 3.0);
+    }
+
+    #[test]
+    fn ema_weight_depends_on_sample_duration() {
+        let mut meter = RateMeter::new(secs(5), secs(1));
+        assert_close(meter.update(at(&meter, 1.0), 1000, None, UNIT).rate, 1000.0);
+        let stats = meter.update(at(&meter, 3.0), 1000, None, UNIT);
+        assert_close(stats.rate, 1000.0 * (-2.0f64 / 5.0).exp());
+
+        // two short samples have the same effect as one long sample
+        let mut meter = RateMeter::new(secs(5), secs(1));
+        meter.update(at(&meter, 1.0), 1000, None, UNIT);
+        meter.update(at(&meter, 2.0), 1000, None, UNIT);
+        let stats = meter.update(at(&meter, 3.0), 1000, None, UNIT);
+        assert_close(stats.rate, 1000.0 * (-2.0f64 / 5.0).exp());
+    }
+
+    #[test]
+    fn peak_ignores_short_samples() {
+        let mut meter = RateMeter::new(secs(5), secs(1));
+        meter.update(at(&meter, 1.0), 1000, None, UNIT);
+        // 50 ms sample with rate 100000
+        let stats = meter.update(at(&meter, 1.05), 6000, None, UNIT);
+        assert_close(stats.peak, 1000.0);
+        let stats = meter.update(at(&meter, 2.05), 9000, None, UNIT);
+        assert_close(stats.peak, 3000.0);
+    }
+
+    #[test]
+    fn peak_with_short_interval() {
+        let mut meter = RateMeter::new(secs(5), Duration::from_millis(50));
+        let stats = meter.update(at(&meter, 0.05), 1000, None, UNIT);
+        assert_close(stats.peak, 20000.0);
+    }
+
+    #[test]
+    fn idle_since_last_increase() {
+        let mut meter = RateMeter::new(secs(5), secs(1));
+        meter.update(at(&meter, 1.0), 1000, None, UNIT);
+        meter.observe(at(&meter, 1.5), 1000);
+        let stats = meter.update(at(&meter, 4.0), 1000, None, UNIT);
+        assert_eq!(stats.idle, secs(3));
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn min_rate_ignores_grace_period() {
+        let mut min_rate = MinRate::new(100, secs(2), secs(5));
+        for t in 0..5 {
+            assert_eq!(min_rate.check(secs(t), 0), None);
+        }
+        assert!(min_rate.samples.is_empty());
+    }
+
+    #[test]
+    fn min_rate_waits_for_full_window() {
+        let mut min_rate = MinRate::new(100, secs(3), secs(1));
+        assert_eq!(min_rate.check(secs(1), 0), None);
+        assert_eq!(min_rate.check(secs(2), 0), None);
+        assert_eq!(min_rate.check(secs(3), 0), None);
+        assert_eq!(min_rate.check(secs(4), 0), Some(0.0));
+    }
+
+    #[test]
+    fn min_rate_over_sliding_window() {
+        let mut min_rate = MinRate::new(100, secs(2), Duration::ZERO);
+        assert_eq!(min_rate.check(secs(0), 0), None);
+        assert_eq!(min_rate.check(secs(1), 1000), None);
+        assert_eq!(min_rate.check(secs(2), 2000), None);
+        // old samples drop out of window
+        assert_eq!(min_rate.check(secs(3), 2100), None);
+        assert_eq!(min_rate.check(secs(4), 2150), Some(75.0));
+        assert_eq!(min_rate.samples.len(), 3);
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    mem,
+    sync::atomic::{self, AtomicBool},
+};
+
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_usr1(_: libc::c_int) {
+    DUMP_REQUESTED.store(true, atomic::Ordering::Relaxed);
+}
+
+extern "C" fn on_int(_: libc::c_int) {
+    INTERRUPTED.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Installs signal handler, interrupted syscalls are restarted, so copy loop does not see EINTR
+pub fn install(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+/// Whether signal is ignored, e.g. inherited from shell for background job or nohup
+fn is_ignored(signal: libc::c_int) -> bool {
+    unsafe {
+        let mut old: libc::sigaction = mem::zeroed();
+        libc::sigaction(signal, std::ptr::null(), &mut old) == 0
+            && old.sa_sigaction == libc::SIG_IGN
+    }
+}
+
+/// Installs handlers of SIGUSR1 (print statistics) and SIGINT (print summary and exit)
+///
+/// Ignored SIGINT stays ignored, so e.g. Ctrl-C does not stop transfer running in background.
+pub fn watch_requests() {
+    install(libc::SIGUSR1, on_usr1);
+    if !is_ignored(libc::SIGINT) {
+        install(libc::SIGINT, on_int);
+    }
+}
+
+/// Whether statistics were requested by SIGUSR1 since last call
+pub fn take_dump_request() -> bool {
+    DUMP_REQUESTED.swap(false, atomic::Ordering::Relaxed)
+}
+
+/// Whether SIGINT was received
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(atomic::Ordering::Relaxed)
+}
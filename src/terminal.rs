@@ -0,0 +1,238 @@
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::fs::{MetadataExt, OpenOptionsExt},
+    },
+    path::PathBuf,
+    sync::atomic::{self, AtomicBool},
+};
+
+use crate::{remote::runtime_dir, signal};
+
+const DEFAULT_TERM_WIDTH: usize = 80;
+// erases from cursor to the end of line
+const CLEAR_TO_EOL: &str = "\x1b[K";
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_winch(_: libc::c_int) {
+    RESIZED.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Installs SIGWINCH handler, which marks terminal size as changed
+pub fn watch_resize() {
+    signal::install(libc::SIGWINCH, on_winch);
+}
+
+fn query_width(fd: RawFd) -> Option<usize> {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    let res = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) };
+    if res < 0 || size.ws_col == 0 {
+        None
+    } else {
+        Some(size.ws_col as usize)
+    }
+}
+
+/// Status line writer, which keeps line within terminal width and clears rest of previous line
+pub struct StatusLine {
+    fd: RawFd,
+    width: usize,
+    is_tty: bool,
+    last_len: usize,
+}
+
+impl StatusLine {
+    pub fn new(fd: RawFd) -> Self {
+        StatusLine {
+            fd,
+            width: query_width(fd).unwrap_or(DEFAULT_TERM_WIDTH),
+            is_tty: unsafe { libc::isatty(fd) } == 1,
+            last_len: 0,
+        }
+    }
+
+    /// Current width, re-queried if terminal was resized
+    pub fn width(&mut self) -> usize {
+        if RESIZED.swap(false, atomic::Ordering::Relaxed) {
+            if let Some(width) = query_width(self.fd) {
+                self.width = width;
+            }
+        }
+        self.width
+    }
+
+    /// Formats line to replace previous one, line is truncated to terminal width
+    pub fn render(&mut self, line: &str) -> String {
+        // keep one column free, so cursor does not wrap to the next line
+        let max = self.width().saturating_sub(1);
+        let mut line: String = line.chars().take(max).collect();
+        let len = line.chars().count();
+        if self.is_tty {
+            line.push_str(CLEAR_TO_EOL);
+        } else if len < self.last_len {
+            line.push_str(&" ".repeat(self.last_len - len));
+        }
+        self.last_len = len;
+        format!("\r{}", line)
+    }
+}
+
+/// Terminal row reserved for one of several rpv instances sharing same terminal (e.g. in one pipeline)
+///
+/// Instances coordinate via lock file keyed by terminal name, which holds number of reserved rows
+/// and slots taken by running processes. Cursor is kept at the first reserved row between draws.
+pub struct CursorSlot {
+    file: File,
+    slot: usize,
+}
+
+/// Content of lock file
+struct Slots {
+    rows: usize,
+    /// (slot, pid)
+    taken: Vec<(usize, u32)>,
+}
+
+impl Slots {
+    fn read(file: &mut File) -> io::Result<Self> {
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut content)?;
+        let mut lines = content.lines();
+        let rows = lines
+            .next()
+            .and_then(|l| l.strip_prefix("rows "))
+            .and_then(|r| r.parse().ok())
+            .unwrap_or(0);
+        let taken = lines
+            .filter_map(|l| {
+                let (slot, pid) = l.split_once(' ')?;
+                Some((slot.parse().ok()?, pid.parse().ok()?))
+            })
+            // slots of processes, which died without releasing them
+            .filter(|&(_, pid)| unsafe { libc::kill(pid as libc::pid_t, 0) } == 0)
+            .collect();
+        Ok(Slots { rows, taken })
+    }
+
+    fn write(&self, file: &mut File) -> io::Result<()> {
+        let mut content = format!("rows {}\n", self.rows);
+        for (slot, pid) in &self.taken {
+            content.push_str(&format!("{} {}\n", slot, pid));
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(content.as_bytes())
+    }
+}
+
+impl CursorSlot {
+    /// Reserves row for this process on terminal fd, which out writes to, fails if fd is not terminal
+    pub fn acquire(fd: RawFd, out: &mut dyn Write) -> io::Result<Self> {
+        let path = lock_path(fd)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            // lock file might be in shared /tmp, so do not follow symlink planted by someone else
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)?;
+        let owner = file.metadata()?.uid();
+        if owner != unsafe { libc::geteuid() } {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is owned by another user", path.display()),
+            ));
+        }
+
+        let slot = with_lock(&mut file, |file| {
+            let mut slots = Slots::read(file)?;
+            if slots.taken.is_empty() {
+                // new block starts at current cursor position
+                slots.rows = 1;
+            }
+            let slot = (0..)
+                .find(|s| slots.taken.iter().all(|(taken, _)| taken != s))
+                .unwrap_or(0);
+            slots.taken.push((slot, std::process::id()));
+            if slot + 1 > slots.rows {
+                // reserve rows, new lines scroll screen, if block is at the bottom
+                write!(out, "{}{}", "\n".repeat(slot), move_up(slot))?;
+                slots.rows = slot + 1;
+            }
+            slots.write(file)?;
+            Ok(slot)
+        })?;
+
+        Ok(CursorSlot { file, slot })
+    }
+
+    /// Draws text (which should start with \r) in reserved row
+    pub fn draw(&mut self, out: &mut dyn Write, text: &str) {
+        let slot = self.slot;
+        let _ = with_lock(&mut self.file, |_| {
+            write!(out, "{}{}{}\r", move_down(slot), text, move_up(slot))
+        });
+    }
+
+    /// Draws final text and releases slot, last process moves cursor below all reserved rows
+    pub fn release(mut self, out: &mut dyn Write, text: &str) {
+        let slot = self.slot;
+        let _ = with_lock(&mut self.file, |file| {
+            write!(out, "{}{}{}\r", move_down(slot), text, move_up(slot))?;
+            let mut slots = Slots::read(file)?;
+            let pid = std::process::id();
+            slots.taken.retain(|&(_, p)| p != pid);
+            if slots.taken.is_empty() {
+                writeln!(out, "{}", move_down(slots.rows.saturating_sub(1)))?;
+                // file is kept, other instances might have it open already and must share the lock
+                slots.rows = 0;
+            }
+            slots.write(file)
+        });
+    }
+}
+
+fn move_down(rows: usize) -> String {
+    if rows > 0 {
+        format!("\x1b[{}B", rows)
+    } else {
+        String::new()
+    }
+}
+
+fn move_up(rows: usize) -> String {
+    if rows > 0 {
+        format!("\x1b[{}A", rows)
+    } else {
+        String::new()
+    }
+}
+
+fn with_lock<T>(file: &mut File, f: impl FnOnce(&mut File) -> io::Result<T>) -> io::Result<T> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let res = f(file);
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+    res
+}
+
+/// Lock file path derived from name of terminal
+fn lock_path(fd: RawFd) -> io::Result<PathBuf> {
+    let mut name = [0 as libc::c_char; 256];
+    let res = unsafe { libc::ttyname_r(fd, name.as_mut_ptr(), name.len()) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy();
+    let key = name.trim_start_matches('/').replace('/', "_");
+    Ok(runtime_dir().join(format!("rpv-cursor-{}.lock", key)))
+}